@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::Serialize;
+use warp::{
+    http::HeaderMap,
+    http::Method,
+    http::StatusCode,
+    reply::Response,
+    Filter, Rejection, Reply,
+};
+
+use crate::proxy::CacheProxy;
+
+
+#[derive(Serialize)]
+struct StatsResponse {
+    entries: usize,
+    total_bytes: u64,
+}
+
+#[derive(Serialize)]
+struct EntryResponse {
+    status: u16,
+    #[serde(with = "http_serde::header_map")]
+    headers: HeaderMap,
+    ctime: DateTime<Utc>,
+    ttl: i64,
+    age: i64,
+    size: u64,
+}
+
+#[derive(Serialize)]
+struct PurgeResponse {
+    purged: usize,
+}
+
+fn json_status<T: Serialize>(body: &T, status: StatusCode) -> Response {
+    warp::reply::with_status(warp::reply::json(body), status).into_response()
+}
+
+/// Checks the `X-Admin-Token` header against the configured admin
+/// token. Returns a ready-made response when the request should be
+/// rejected: `404` if the admin API isn't configured at all (so it
+/// doesn't leak its own existence), `401` on a missing/wrong token.
+fn reject_unless_authorized(proxy: &CacheProxy, headers: &HeaderMap) -> Option<Response> {
+    let expected = match proxy.admin_token() {
+        Some(token) => token,
+        None => return Some(json_status(&PurgeResponse { purged: 0 }, StatusCode::NOT_FOUND)),
+    };
+    let provided = headers.get("X-Admin-Token").and_then(|v| v.to_str().ok());
+    // compare hashes of the tokens, not the tokens themselves, so a guess
+    // that's wrong in its first byte takes the same time to reject as one
+    // that's wrong in its last
+    let matches = provided
+        .map(|token| blake3::hash(token.as_bytes()) == blake3::hash(expected.as_bytes()))
+        .unwrap_or(false);
+    if matches {
+        None
+    } else {
+        warn!("admin API request rejected, missing or wrong admin token");
+        Some(json_status(&PurgeResponse { purged: 0 }, StatusCode::UNAUTHORIZED))
+    }
+}
+
+fn with_proxy(
+    proxy: Arc<CacheProxy>,
+) -> impl Filter<Extract = (Arc<CacheProxy>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || Arc::clone(&proxy))
+}
+
+async fn stats_handler(proxy: Arc<CacheProxy>, headers: HeaderMap) -> Result<Response, Rejection> {
+    if let Some(rejected) = reject_unless_authorized(&proxy, &headers) {
+        return Ok(rejected);
+    }
+    let stats = proxy.cache_stats();
+    Ok(json_status(&StatsResponse { entries: stats.entries, total_bytes: stats.total_bytes }, StatusCode::OK))
+}
+
+/// Handles both `GET /_cache/entry/{hash}` (inspect) and
+/// `DELETE /_cache/entry/{hash}` (purge one entry).
+async fn entry_handler(hash: String, method: Method, proxy: Arc<CacheProxy>, headers: HeaderMap) -> Result<Response, Rejection> {
+    if let Some(rejected) = reject_unless_authorized(&proxy, &headers) {
+        return Ok(rejected);
+    }
+    if method == Method::DELETE {
+        let existed = proxy.cache_purge(&hash);
+        let status = if existed { StatusCode::OK } else { StatusCode::NOT_FOUND };
+        return Ok(json_status(&PurgeResponse { purged: usize::from(existed) }, status));
+    }
+    if method != Method::GET {
+        return Ok(json_status(&PurgeResponse { purged: 0 }, StatusCode::METHOD_NOT_ALLOWED));
+    }
+    match proxy.cache_peek(&hash) {
+        Ok(Some(entry)) => Ok(json_status(
+            &EntryResponse {
+                status: entry.status.as_u16(),
+                headers: entry.headers,
+                ctime: entry.ctime,
+                ttl: entry.ttl,
+                age: entry.age,
+                size: entry.size,
+            },
+            StatusCode::OK,
+        )),
+        Ok(None) => Ok(json_status(&PurgeResponse { purged: 0 }, StatusCode::NOT_FOUND)),
+        Err(e) => {
+            warn!("[{}] admin entry lookup failed: {}", &hash[..hash.len().min(6)], e);
+            Ok(json_status(&PurgeResponse { purged: 0 }, StatusCode::INTERNAL_SERVER_ERROR))
+        }
+    }
+}
+
+/// Handles `DELETE /_cache/entries`: purges the whole cache, or only
+/// entries whose key starts with `?prefix=...` if given.
+async fn purge_entries_handler(
+    proxy: Arc<CacheProxy>,
+    headers: HeaderMap,
+    query: HashMap<String, String>,
+) -> Result<Response, Rejection> {
+    if let Some(rejected) = reject_unless_authorized(&proxy, &headers) {
+        return Ok(rejected);
+    }
+    let purged = match query.get("prefix") {
+        Some(prefix) => proxy.cache_purge_prefix(prefix),
+        None => proxy.cache_clear(),
+    };
+    Ok(json_status(&PurgeResponse { purged }, StatusCode::OK))
+}
+
+/// Builds the `/_cache/...` admin routes for cache inspection and
+/// selective purge, every one gated behind `X-Admin-Token`:
+/// `GET stats`, `GET`/`DELETE entry/{hash}`, `DELETE entries[?prefix=]`.
+pub fn routes(proxy: Arc<CacheProxy>) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let stats = warp::path!("_cache" / "stats")
+        .and(warp::get())
+        .and(with_proxy(Arc::clone(&proxy)))
+        .and(warp::header::headers_cloned())
+        .and_then(stats_handler);
+
+    let entry = warp::path!("_cache" / "entry" / String)
+        .and(warp::method())
+        .and(with_proxy(Arc::clone(&proxy)))
+        .and(warp::header::headers_cloned())
+        .and_then(entry_handler);
+
+    let entries = warp::path!("_cache" / "entries")
+        .and(warp::delete())
+        .and(with_proxy(proxy))
+        .and(warp::header::headers_cloned())
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(purge_entries_handler);
+
+    stats.or(entry).unify().or(entries).unify()
+}
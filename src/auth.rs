@@ -0,0 +1,201 @@
+use std::collections::HashSet;
+
+use chrono::Utc;
+use warp::{http::HeaderMap};
+
+use warp_reverse_proxy::QueryParameters;
+
+
+/// Result of validating a client-supplied token.
+#[derive(Debug, PartialEq, Eq)]
+pub enum TokenOutcome {
+    /// Token checked out; carries the identity folded into the cache key
+    /// (empty when token gating is disabled, i.e. no isolation needed).
+    Valid(String),
+    Missing,
+    Expired,
+    Invalid,
+}
+
+/// Gates access to the proxy behind an optional token, either a signed
+/// shared-secret token or a static allowlist of opaque tokens. Disabled
+/// entirely when neither header nor query param is configured.
+pub struct TokenValidator {
+    header: Option<String>,
+    query_param: Option<String>,
+    shared_secret: Option<String>,
+    allowlist: HashSet<String>,
+}
+
+impl TokenValidator {
+    pub fn new(
+        header: Option<String>,
+        query_param: Option<String>,
+        shared_secret: Option<String>,
+        allowlist: Vec<String>,
+    ) -> Self {
+        TokenValidator {
+            header,
+            query_param,
+            shared_secret,
+            allowlist: allowlist.into_iter().collect(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.header.is_some() || self.query_param.is_some()
+    }
+
+    fn extract_token(&self, headers: &HeaderMap, params: &QueryParameters) -> Option<String> {
+        if let Some(name) = &self.header {
+            if let Some(value) = headers.get(name).and_then(|v| v.to_str().ok()) {
+                return Some(value.to_owned());
+            }
+        }
+        if let Some(name) = &self.query_param {
+            if let Some(query) = params {
+                for pair in query.split('&') {
+                    let mut kv = pair.splitn(2, '=');
+                    if kv.next() == Some(name.as_str()) {
+                        if let Some(value) = kv.next() {
+                            return Some(value.to_owned());
+                        }
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// Validates a token pulled from the configured header/query
+    /// parameter. Returns `Valid("")` when gating is disabled.
+    pub fn validate(&self, headers: &HeaderMap, params: &QueryParameters) -> TokenOutcome {
+        if !self.is_enabled() {
+            return TokenOutcome::Valid(String::new());
+        }
+
+        let token = match self.extract_token(headers, params) {
+            Some(token) => token,
+            None => return TokenOutcome::Missing,
+        };
+
+        match &self.shared_secret {
+            Some(secret) => self.validate_signed(&token, secret),
+            None => {
+                if self.allowlist.contains(&token) {
+                    TokenOutcome::Valid(token)
+                } else {
+                    TokenOutcome::Invalid
+                }
+            }
+        }
+    }
+
+    /// Validates a token of the form `<identity>:<expiry-unix>.<mac>`,
+    /// where `mac` is a keyed blake3 hash of `<identity>:<expiry-unix>`
+    /// under `secret`.
+    fn validate_signed(&self, token: &str, secret: &str) -> TokenOutcome {
+        let mut parts = token.rsplitn(2, '.');
+        let mac_hex = match parts.next() {
+            Some(mac) => mac,
+            None => return TokenOutcome::Invalid,
+        };
+        let payload = match parts.next() {
+            Some(payload) => payload,
+            None => return TokenOutcome::Invalid,
+        };
+
+        let key = blake3::hash(secret.as_bytes());
+        let expected = blake3::keyed_hash(key.as_bytes(), payload.as_bytes());
+        // parse the hex MAC back into a `blake3::Hash` rather than
+        // comparing hex strings: a forged token differing by one byte
+        // must not be distinguishable from one differing by all of them
+        let provided = match blake3::Hash::from_hex(mac_hex) {
+            Ok(hash) => hash,
+            Err(_) => return TokenOutcome::Invalid,
+        };
+        if expected != provided {
+            return TokenOutcome::Invalid;
+        }
+
+        let mut payload_parts = payload.splitn(2, ':');
+        let identity = match payload_parts.next() {
+            Some(identity) if !identity.is_empty() => identity,
+            _ => return TokenOutcome::Invalid,
+        };
+        if let Some(expiry) = payload_parts.next().and_then(|s| s.parse::<i64>().ok()) {
+            if Utc::now().timestamp() > expiry {
+                return TokenOutcome::Expired;
+            }
+        }
+        TokenOutcome::Valid(identity.to_owned())
+    }
+}
+
+/// Folds a validated token identity into an already-issued cache key, so
+/// different clients never share cached entries.
+pub fn fold_identity(base: &str, identity: &str) -> String {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(base.as_bytes());
+    hasher.update(identity.as_bytes());
+    hasher.finalize().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sign(secret: &str, identity: &str, expiry: i64) -> String {
+        let payload = format!("{}:{}", identity, expiry);
+        let key = blake3::hash(secret.as_bytes());
+        let mac = blake3::keyed_hash(key.as_bytes(), payload.as_bytes());
+        format!("{}.{}", payload, mac.to_hex())
+    }
+
+    fn headers_with_token(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Cache-Token", token.parse().unwrap());
+        headers
+    }
+
+    fn validator(secret: &str) -> TokenValidator {
+        TokenValidator::new(
+            Some("X-Cache-Token".to_owned()), None, Some(secret.to_owned()), vec![]
+        )
+    }
+
+    #[test]
+    fn missing_token_is_rejected() {
+        let outcome = validator("s3cr3t").validate(&HeaderMap::new(), &None);
+        assert_eq!(outcome, TokenOutcome::Missing);
+    }
+
+    #[test]
+    fn forged_token_is_rejected() {
+        let token = sign("s3cr3t", "tenant-a", Utc::now().timestamp() + 3600);
+        let forged = token.replace("tenant-a", "tenant-b"); // payload changed, mac stale
+        let outcome = validator("s3cr3t").validate(&headers_with_token(&forged), &None);
+        assert_eq!(outcome, TokenOutcome::Invalid);
+    }
+
+    #[test]
+    fn expired_token_is_rejected() {
+        let token = sign("s3cr3t", "tenant-a", Utc::now().timestamp() - 1);
+        let outcome = validator("s3cr3t").validate(&headers_with_token(&token), &None);
+        assert_eq!(outcome, TokenOutcome::Expired);
+    }
+
+    #[test]
+    fn valid_token_yields_identity() {
+        let token = sign("s3cr3t", "tenant-a", Utc::now().timestamp() + 3600);
+        let outcome = validator("s3cr3t").validate(&headers_with_token(&token), &None);
+        assert_eq!(outcome, TokenOutcome::Valid("tenant-a".to_owned()));
+    }
+
+    #[test]
+    fn disabled_validator_is_always_valid() {
+        let validator = TokenValidator::new(None, None, None, vec![]);
+        let outcome = validator.validate(&HeaderMap::new(), &None);
+        assert_eq!(outcome, TokenOutcome::Valid(String::new()));
+    }
+}
@@ -1,7 +1,11 @@
 use std::error::Error;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use chrono::{DateTime, Utc};
 use log::{info};
+use lru::LruCache;
 use serde::{Deserialize, Serialize};
 
 use warp::{
@@ -13,16 +17,36 @@ use warp::{
     filters::path::FullPath
 };
 
+use warp_reverse_proxy::{Method, QueryParameters};
+
 
 /// Cache configuration
 pub trait CacheConfig {
     fn get_db_path(&self) -> String;
-    fn get_ttl(&self) -> i64 { 3600 }  // default 1 hour
+    fn get_ttl(&self) -> i64 { 3600 }  // default 1 hour, used when the origin gives no TTL hint
+    /// Maximum number of entries kept on disk, or `None` for unbounded.
+    fn get_max_entries(&self) -> Option<usize> { None }
+    /// Maximum total size in bytes of cached response bodies and
+    /// metadata, or `None` for unbounded.
+    fn get_max_bytes(&self) -> Option<u64> { None }
+    /// Grace window, in seconds, past an entry's TTL during which it is
+    /// still served (stale) while a background refresh is triggered.
+    fn get_stale_while_revalidate(&self) -> i64 { 0 }
 }
 
+/// Bumped whenever `CacheEntry`'s fields change shape. `bincode` is a
+/// positional format with no self-describing schema, so a stored entry
+/// from an older version either fails to deserialize outright or (worse)
+/// silently decodes into garbage field values; this tag lets us reject
+/// the latter case too instead of serving it.
+const CACHE_ENTRY_VERSION: u8 = 1;
+
 /// Cache entry -- saved response data
 #[derive(Serialize, Deserialize)]
 struct CacheEntry {
+    // schema version this entry was written with, see `CACHE_ENTRY_VERSION`
+    version: u8,
+
     // response status code
     #[serde(with = "http_serde::status_code")]
     status: StatusCode,
@@ -37,6 +61,34 @@ struct CacheEntry {
 
     // date and time entry creation
     ctime: DateTime<Utc>,
+
+    // per-entry Time-To-Live in seconds, derived from the origin's
+    // Cache-Control/Expires headers, falling back to the configured default
+    ttl: i64,
+}
+
+/// Outcome of a cache lookup: found and fresh, found but past its TTL
+/// (within the stale-while-revalidate grace window), or not usable.
+pub enum CacheLookup {
+    Fresh(Response<Bytes>),
+    Stale(Response<Bytes>),
+    Miss,
+}
+
+/// Aggregate counters returned by `DataCache::stats`, for the admin API.
+pub struct CacheStats {
+    pub entries: usize,
+    pub total_bytes: u64,
+}
+
+/// Metadata-only view of a cached entry, returned by `DataCache::peek`.
+pub struct EntryMeta {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub ctime: DateTime<Utc>,
+    pub ttl: i64,
+    pub age: i64,
+    pub size: u64,
 }
 
 /// Keys for add header values to cached response, if any
@@ -69,20 +121,79 @@ impl CacheEntry {
         builder.body(Bytes::from(self.body))
     }
 
-    fn from_response(response: &Response<Bytes>) -> Self {
-        CacheEntry {
+    /// Builds an entry from an upstream response, honoring its
+    /// `Cache-Control`/`Expires` headers. Returns `None` if the origin
+    /// marked the response `no-store`/`private`, meaning it must not be
+    /// persisted at all.
+    fn from_response(response: &Response<Bytes>, default_ttl: i64) -> Option<Self> {
+        let ttl = match cache_control_ttl(response.headers()) {
+            Some(CacheControlTtl::DoNotStore) => return None,
+            Some(CacheControlTtl::MaxAge(max_age)) => max_age,
+            None => expires_ttl(response.headers()).unwrap_or(default_ttl),
+        };
+
+        Some(CacheEntry {
+            version: CACHE_ENTRY_VERSION,
             status: response.status(),
             headers: response.headers().to_owned(),
             body: response.body().to_vec(),
-            ctime: Utc::now()
+            ctime: Utc::now(),
+            ttl,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum CacheControlTtl {
+    DoNotStore,
+    MaxAge(i64),
+}
+
+/// Parses the `Cache-Control` response header for `no-store`/`private`
+/// (never cache) and `max-age` (per-entry TTL).
+fn cache_control_ttl(headers: &HeaderMap) -> Option<CacheControlTtl> {
+    let value = headers.get(warp::http::header::CACHE_CONTROL)?.to_str().ok()?;
+    let mut max_age = None;
+    for directive in value.split(',').map(|d| d.trim()) {
+        let mut parts = directive.splitn(2, '=');
+        let name = parts.next().unwrap_or("").to_ascii_lowercase();
+        match name.as_str() {
+            "no-store" | "private" => return Some(CacheControlTtl::DoNotStore),
+            "max-age" => {
+                if let Some(seconds) = parts.next().and_then(|v| v.trim().parse::<i64>().ok()) {
+                    max_age = Some(seconds);
+                }
+            }
+            _ => {}
         }
     }
+    max_age.map(CacheControlTtl::MaxAge)
+}
+
+/// Derives a TTL in seconds from an `Expires` response header, relative
+/// to now.
+fn expires_ttl(headers: &HeaderMap) -> Option<i64> {
+    let value = headers.get(warp::http::header::EXPIRES)?.to_str().ok()?;
+    let expires = DateTime::parse_from_rfc2822(value).ok()?;
+    Some((expires.with_timezone(&Utc) - Utc::now()).num_seconds().max(0))
 }
 
+/// How often the background sweeper checks for expired and over-budget entries.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Tracks recency and size of entries so the budget below can be enforced
+/// with a least-recently-used eviction order.
+type LruIndex = Mutex<LruCache<String, u64>>;
+
 /// Cache for responses data
 pub struct DataCache {
-    db: sled::Db, // Cache database
-    ttl: i64,     // Data Time-To-Live in seconds
+    db: sled::Db,          // Cache database
+    ttl: i64,               // default Data Time-To-Live in seconds
+    max_entries: Option<usize>,
+    max_bytes: Option<u64>,
+    stale_grace: i64,       // stale-while-revalidate grace window, in seconds
+    index: Arc<LruIndex>,   // access-order index, keyed by hash
+    total_bytes: Arc<AtomicU64>,
 }
 
 impl DataCache {
@@ -98,68 +209,581 @@ impl DataCache {
         let db: sled::Db = db_config.open()
             .expect(format!("error opening cache database: {}", config.get_db_path()).as_str());
 
-        DataCache {
-            db: db,
+        // rebuild the index from what's already on disk, so a restart
+        // doesn't lose track of the current size budget. No access time is
+        // persisted anywhere, so this only recovers entry/byte counts, not
+        // real recency: `db.iter()` yields entries in key order, and the
+        // LRU eviction order is effectively arbitrary until each entry is
+        // touched again by a lookup or insert after startup
+        let mut index = LruCache::unbounded();
+        let mut total_bytes = 0u64;
+        for item in db.iter() {
+            if let Ok((key, value)) = item {
+                if let Ok(hash) = String::from_utf8(key.to_vec()) {
+                    let size = value.len() as u64;
+                    total_bytes += size;
+                    index.put(hash, size);
+                }
+            }
+        }
+
+        let cache = DataCache {
+            db,
             ttl: config.get_ttl(),
+            max_entries: config.get_max_entries(),
+            max_bytes: config.get_max_bytes(),
+            stale_grace: config.get_stale_while_revalidate(),
+            index: Arc::new(Mutex::new(index)),
+            total_bytes: Arc::new(AtomicU64::new(total_bytes)),
+        };
+
+        cache.evict_over_budget();
+        cache.spawn_sweeper();
+        cache
+    }
+
+    /// Spawns the background task that periodically evicts expired
+    /// entries and enforces the configured size/count budget, so stale
+    /// and over-budget data doesn't accumulate between requests.
+    fn spawn_sweeper(&self) {
+        let db = self.db.clone();
+        let index = Arc::clone(&self.index);
+        let total_bytes = Arc::clone(&self.total_bytes);
+        let stale_grace = self.stale_grace;
+        let max_entries = self.max_entries;
+        let max_bytes = self.max_bytes;
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+            loop {
+                interval.tick().await;
+                Self::sweep_expired(&db, &index, &total_bytes, stale_grace);
+                Self::evict_until_within_budget(&db, &index, &total_bytes, max_entries, max_bytes);
+            }
+        });
+    }
+
+    /// Removes every entry whose own TTL (plus the stale-while-revalidate
+    /// grace window) has elapsed, so entries still eligible to be served
+    /// stale are left alone. Entries that fail to deserialize or carry an
+    /// unexpected schema version are corrupt or left over from an older
+    /// version of `CacheEntry` and are removed outright, rather than
+    /// being silently skipped and left to accumulate as dead weight.
+    fn sweep_expired(db: &sled::Db, index: &LruIndex, total_bytes: &AtomicU64, stale_grace: i64) {
+        let mut expired = Vec::new();
+        for item in db.iter() {
+            if let Ok((key, value)) = item {
+                if let Ok(hash) = String::from_utf8(key.to_vec()) {
+                    match bincode::deserialize::<CacheEntry>(&value) {
+                        Ok(entry) if entry.version == CACHE_ENTRY_VERSION => {
+                            let age = (Utc::now() - entry.ctime).num_seconds();
+                            if age > entry.ttl + stale_grace {
+                                expired.push(hash);
+                            }
+                        }
+                        _ => expired.push(hash),
+                    }
+                }
+            }
+        }
+        for hash in expired {
+            info!("[{}] expired or unreadable, removing from cache database", &hash[..6]);
+            Self::remove_entry(db, index, total_bytes, &hash);
+        }
+    }
+
+    /// Evicts least-recently-used entries until the configured
+    /// `max_entries`/`max_bytes` budget is satisfied.
+    fn evict_until_within_budget(
+        db: &sled::Db,
+        index: &LruIndex,
+        total_bytes: &AtomicU64,
+        max_entries: Option<usize>,
+        max_bytes: Option<u64>,
+    ) {
+        loop {
+            let over_budget = {
+                let guard = index.lock().unwrap();
+                let over_entries = max_entries.map_or(false, |max| guard.len() > max);
+                let over_bytes = max_bytes.map_or(false, |max| total_bytes.load(Ordering::Relaxed) > max);
+                over_entries || over_bytes
+            };
+            if !over_budget {
+                break;
+            }
+            let evicted = index.lock().unwrap().pop_lru();
+            match evicted {
+                Some((hash, size)) => {
+                    info!("[{}] evicting LRU entry, size={}", &hash[..6], size);
+                    let _ = db.remove(&hash);
+                    total_bytes.fetch_sub(size, Ordering::Relaxed);
+                }
+                None => break, // index empty, nothing left to evict
+            }
         }
     }
 
-    pub fn get(&self, hash: &str) -> Result<Option<Response<Bytes>>, Box<dyn Error>> {
-        if let Some(data) = self.db.get(&hash).unwrap() {
-            let entry: CacheEntry = bincode::deserialize(&data)?;
-            // test entry ttl
+    fn evict_over_budget(&self) {
+        Self::evict_until_within_budget(&self.db, &self.index, &self.total_bytes, self.max_entries, self.max_bytes);
+    }
+
+    fn remove_entry(db: &sled::Db, index: &LruIndex, total_bytes: &AtomicU64, hash: &str) {
+        let _ = db.remove(hash);
+        if let Some(size) = index.lock().unwrap().pop(hash) {
+            total_bytes.fetch_sub(size, Ordering::Relaxed);
+        }
+    }
+
+    /// Number of entries currently tracked in the cache.
+    pub fn entry_count(&self) -> usize {
+        self.index.lock().unwrap().len()
+    }
+
+    /// Approximate total size in bytes of all cached entries.
+    pub fn total_size(&self) -> u64 {
+        self.total_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Loads and decodes the entry stored under `hash`. An entry that
+    /// fails to deserialize or carries an unexpected schema version is
+    /// corrupt or left over from an older version of `CacheEntry`; it's
+    /// purged on the spot rather than returned or left behind for the
+    /// sweeper to skip over indefinitely.
+    fn load_entry(&self, hash: &str) -> Result<Option<CacheEntry>, Box<dyn Error>> {
+        let data = match self.db.get(&hash).unwrap() {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+        match bincode::deserialize::<CacheEntry>(&data) {
+            Ok(entry) if entry.version == CACHE_ENTRY_VERSION => Ok(Some(entry)),
+            _ => {
+                info!("[{}] entry unreadable or outdated schema, removing", &hash[..hash.len().min(6)]);
+                Self::remove_entry(&self.db, &self.index, &self.total_bytes, hash);
+                Ok(None)
+            }
+        }
+    }
+
+    fn entry_to_response(&self, hash: &str, entry: CacheEntry) -> Result<Option<Response<Bytes>>, Box<dyn Error>> {
+        // check size of body; if empty - return None
+        let size = entry.body.len();
+        if size > 0 {
+            Ok(Some(entry.to_response()?))
+        } else {
+            info!(
+                "[{}] sorry, result size={}, skipping...",
+                &hash[..6],
+                size
+            );
+            Ok(None)
+        }
+    }
+
+    /// Looks up `hash`, classifying the result against the entry's own
+    /// TTL (derived from the origin's `Cache-Control`/`Expires` headers):
+    /// fresh, stale-but-within-grace (caller should serve it and trigger
+    /// a background revalidation), or a miss.
+    pub fn get(&self, hash: &str) -> Result<CacheLookup, Box<dyn Error>> {
+        let entry = match self.load_entry(hash)? {
+            Some(entry) => entry,
+            None => return Ok(CacheLookup::Miss),
+        };
+
+        let age = (Utc::now() - entry.ctime).num_seconds();
+        info!(
+            "[{}] found result in cache database, age={}, ttl={}",
+            &hash[..6], age, entry.ttl
+        );
+
+        if age <= entry.ttl {
+            self.index.lock().unwrap().get(&hash.to_string());
+            Ok(self.entry_to_response(hash, entry)?.map_or(CacheLookup::Miss, CacheLookup::Fresh))
+        } else if age <= entry.ttl + self.stale_grace {
+            info!("[{}] entry past TTL but within stale grace window, serving stale", &hash[..6]);
+            self.index.lock().unwrap().get(&hash.to_string());
+            Ok(self.entry_to_response(hash, entry)?.map_or(CacheLookup::Miss, CacheLookup::Stale))
+        } else {
+            info!(
+                "[{}] sorry, result too old, ttl={}, skipping...",
+                &hash[..6], entry.ttl
+            );
+            Ok(CacheLookup::Miss)
+        }
+    }
+
+    /// Like `get`, but ignores TTL expiry entirely and returns the entry
+    /// no matter how old. Used as a graceful fallback when the origin is
+    /// unreachable (upstream timeout, retries exhausted, or an open
+    /// circuit breaker).
+    pub fn get_stale(&self, hash: &str) -> Result<Option<Response<Bytes>>, Box<dyn Error>> {
+        if let Some(entry) = self.load_entry(hash)? {
             let age = (Utc::now() - entry.ctime).num_seconds();
             info!(
-                "[{}] found result in cache database, age={}",
+                "[{}] serving stale entry as fallback, age={}",
                 &hash[..6],
                 age
             );
-            if age > self.ttl {
-                // entry too old
-                info!(
-                    "[{}] sorry, result too old, config ttl={}, skipping...",
-                    &hash[..6],
-                    self.ttl
-                );
-                Ok(None)
-            } else {
-                // check size of body
-                // if empty - return None
-                let size = entry.body.len();
-                if size > 0 {
-                    // Build response
-                    Ok(Some(entry.to_response()?))
-                } else {
-                    info!(
-                        "[{}] sorry, result size={}, skipping...",
-                        &hash[..6],
-                        size
-                    );
-                    Ok(None)
-                }
-            }
+            self.index.lock().unwrap().get(&hash.to_string());
+            self.entry_to_response(hash, entry)
         } else {
-            // not found
             Ok(None)
         }
     }
 
+    /// Snapshot of the cache's current size, for the admin stats endpoint.
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.entry_count(),
+            total_bytes: self.total_size(),
+        }
+    }
+
+    /// Metadata-only view of a cached entry (no body), for the admin
+    /// inspect endpoint. Doesn't count as an access, unlike `get`.
+    pub fn peek(&self, hash: &str) -> Result<Option<EntryMeta>, Box<dyn Error>> {
+        let entry = match self.load_entry(hash)? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let size = self.index.lock().unwrap().peek(hash).copied().unwrap_or(0);
+        Ok(Some(EntryMeta {
+            status: entry.status,
+            headers: entry.headers,
+            ctime: entry.ctime,
+            ttl: entry.ttl,
+            age: (Utc::now() - entry.ctime).num_seconds(),
+            size,
+        }))
+    }
+
+    /// Removes a single entry. Returns whether it was present.
+    pub fn purge(&self, hash: &str) -> bool {
+        let existed = self.index.lock().unwrap().contains(hash);
+        Self::remove_entry(&self.db, &self.index, &self.total_bytes, hash);
+        existed
+    }
+
+    /// Removes every entry whose key starts with `prefix`. Returns the
+    /// number of entries removed.
+    pub fn purge_prefix(&self, prefix: &str) -> usize {
+        let matching: Vec<String> = self.db.iter().keys()
+            .filter_map(|key| key.ok())
+            .filter_map(|key| String::from_utf8(key.to_vec()).ok())
+            .filter(|hash| hash.starts_with(prefix))
+            .collect();
+        for hash in &matching {
+            Self::remove_entry(&self.db, &self.index, &self.total_bytes, hash);
+        }
+        matching.len()
+    }
+
+    /// Removes every entry from the cache. Returns the number removed.
+    pub fn clear(&self) -> usize {
+        let hashes: Vec<String> = self.index.lock().unwrap().iter().map(|(hash, _)| hash.clone()).collect();
+        for hash in &hashes {
+            Self::remove_entry(&self.db, &self.index, &self.total_bytes, hash);
+        }
+        hashes.len()
+    }
+
     pub fn insert(&self, hash: &str, response: &Response<Bytes>) -> Result<(), Box<dyn Error>> {
-        let entry = CacheEntry::from_response(response);
-        match self.db.insert(hash, bincode::serialize(&entry)?) {
-            Ok(_) => Ok(()),
+        let entry = match CacheEntry::from_response(response, self.ttl) {
+            Some(entry) => entry,
+            None => {
+                info!("[{}] origin marked response no-store/private, not caching", &hash[..6]);
+                return Ok(());
+            }
+        };
+        let data = bincode::serialize(&entry)?;
+        let size = data.len() as u64;
+
+        match self.db.insert(hash, data) {
+            Ok(previous) => {
+                let previous_size = previous.map_or(0, |v| v.len() as u64);
+                self.total_bytes.fetch_add(size, Ordering::Relaxed);
+                if previous_size > 0 {
+                    self.total_bytes.fetch_sub(previous_size, Ordering::Relaxed);
+                }
+                self.index.lock().unwrap().put(hash.to_string(), size);
+
+                Self::evict_until_within_budget(
+                    &self.db, &self.index, &self.total_bytes, self.max_entries, self.max_bytes
+                );
+                Ok(())
+            }
             Err(err) => Err(Box::new(err))
-        } 
+        }
     }
 }
 
-/// Generate hash string for request uri and body
-pub fn rq_hash_string(uri: &FullPath, body: &Bytes) -> String {
-    let mut hasher = blake3::Hasher::new();
-    // hash uri
-    hasher.update(uri.as_str().as_bytes());
-    // hash request body
-    hasher.update(body);
-    // return hash string
-    hasher.finalize().to_string()
+/// Issues cache keys for incoming requests, or opts a request out of
+/// caching entirely by returning `None`. A pluggable extension point in
+/// place of a single fixed hashing scheme, so callers can compose issuers
+/// that fold extra request data (query params, tenant headers) into the
+/// key, or that skip caching for certain paths altogether.
+pub trait CacheIssuer: Send + Sync {
+    fn issue(
+        &self,
+        method: &Method,
+        uri: &FullPath,
+        params: &QueryParameters,
+        headers: &HeaderMap,
+        body: &Bytes,
+    ) -> Option<String>;
+}
+
+/// Default issuer: hashes method, uri, query string and body.
+///
+/// This is the original `rq_hash_string` scheme (uri and body) plus the
+/// method and query parameters, so two requests differing only in query
+/// string no longer collide in the cache.
+pub struct DefaultCacheIssuer;
+
+impl CacheIssuer for DefaultCacheIssuer {
+    fn issue(
+        &self,
+        method: &Method,
+        uri: &FullPath,
+        params: &QueryParameters,
+        _headers: &HeaderMap,
+        body: &Bytes,
+    ) -> Option<String> {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(method.as_str().as_bytes());
+        hasher.update(uri.as_str().as_bytes());
+        if let Some(query) = params {
+            hasher.update(query.as_bytes());
+        }
+        hasher.update(body);
+        Some(hasher.finalize().to_string())
+    }
+}
+
+/// Wraps an issuer and folds the values of selected request headers
+/// (e.g. `Accept`, a tenant id) into the resulting key, so responses
+/// that vary by one of those headers get distinct cache entries.
+pub struct HeaderFoldingIssuer<I> {
+    inner: I,
+    fold_headers: Vec<String>,
+}
+
+impl<I: CacheIssuer> HeaderFoldingIssuer<I> {
+    pub fn new(inner: I, fold_headers: Vec<String>) -> Self {
+        HeaderFoldingIssuer { inner, fold_headers }
+    }
+}
+
+impl<I: CacheIssuer> CacheIssuer for HeaderFoldingIssuer<I> {
+    fn issue(
+        &self,
+        method: &Method,
+        uri: &FullPath,
+        params: &QueryParameters,
+        headers: &HeaderMap,
+        body: &Bytes,
+    ) -> Option<String> {
+        let base = self.inner.issue(method, uri, params, headers, body)?;
+        if self.fold_headers.is_empty() {
+            return Some(base);
+        }
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(base.as_bytes());
+        for name in &self.fold_headers {
+            if let Some(value) = headers.get(name) {
+                hasher.update(value.as_bytes());
+            }
+        }
+        Some(hasher.finalize().to_string())
+    }
+}
+
+/// Wraps an issuer and opts requests whose path starts with one of the
+/// configured prefixes out of caching entirely, regardless of what the
+/// wrapped issuer would have returned.
+pub struct PathExcludingIssuer<I> {
+    inner: I,
+    exclude_prefixes: Vec<String>,
+}
+
+impl<I: CacheIssuer> PathExcludingIssuer<I> {
+    pub fn new(inner: I, exclude_prefixes: Vec<String>) -> Self {
+        PathExcludingIssuer { inner, exclude_prefixes }
+    }
+}
+
+impl<I: CacheIssuer> CacheIssuer for PathExcludingIssuer<I> {
+    fn issue(
+        &self,
+        method: &Method,
+        uri: &FullPath,
+        params: &QueryParameters,
+        headers: &HeaderMap,
+        body: &Bytes,
+    ) -> Option<String> {
+        if self.exclude_prefixes.iter().any(|prefix| uri.as_str().starts_with(prefix.as_str())) {
+            return None;
+        }
+        self.inner.issue(method, uri, params, headers, body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seeded_index(db: &sled::Db, entries: &[(&str, u64)]) -> (LruIndex, AtomicU64) {
+        let mut index = LruCache::unbounded();
+        let mut total = 0u64;
+        for (hash, size) in entries {
+            db.insert(*hash, vec![0u8; *size as usize]).unwrap();
+            index.put(hash.to_string(), *size);
+            total += size;
+        }
+        (Mutex::new(index), AtomicU64::new(total))
+    }
+
+    #[test]
+    fn evicts_down_to_entry_count_budget() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let (index, total_bytes) = seeded_index(&db, &[("a", 10), ("b", 10), ("c", 10)]);
+
+        DataCache::evict_until_within_budget(&db, &index, &total_bytes, Some(2), None);
+
+        assert_eq!(index.lock().unwrap().len(), 2);
+        assert_eq!(db.len(), 2);
+    }
+
+    #[test]
+    fn evicts_down_to_byte_budget() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let (index, total_bytes) = seeded_index(&db, &[("a", 10), ("b", 10), ("c", 10)]);
+
+        DataCache::evict_until_within_budget(&db, &index, &total_bytes, None, Some(15));
+
+        assert!(total_bytes.load(Ordering::Relaxed) <= 15);
+        assert_eq!(db.len(), 1);
+    }
+
+    #[test]
+    fn evicts_single_entry_larger_than_the_whole_budget() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let (index, total_bytes) = seeded_index(&db, &[("a", 100)]);
+
+        DataCache::evict_until_within_budget(&db, &index, &total_bytes, None, Some(10));
+
+        assert_eq!(index.lock().unwrap().len(), 0);
+        assert_eq!(total_bytes.load(Ordering::Relaxed), 0);
+    }
+
+    fn headers_with(name: warp::http::header::HeaderName, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn cache_control_no_store_wins_even_alongside_max_age() {
+        let headers = headers_with(warp::http::header::CACHE_CONTROL, "max-age=60, no-store");
+        assert_eq!(cache_control_ttl(&headers), Some(CacheControlTtl::DoNotStore));
+    }
+
+    #[test]
+    fn cache_control_private_is_also_do_not_store() {
+        let headers = headers_with(warp::http::header::CACHE_CONTROL, "private");
+        assert_eq!(cache_control_ttl(&headers), Some(CacheControlTtl::DoNotStore));
+    }
+
+    #[test]
+    fn cache_control_parses_max_age_among_other_directives() {
+        let headers = headers_with(warp::http::header::CACHE_CONTROL, "public, max-age=120");
+        assert_eq!(cache_control_ttl(&headers), Some(CacheControlTtl::MaxAge(120)));
+    }
+
+    #[test]
+    fn cache_control_passes_negative_max_age_through_unclamped() {
+        let headers = headers_with(warp::http::header::CACHE_CONTROL, "max-age=-5");
+        assert_eq!(cache_control_ttl(&headers), Some(CacheControlTtl::MaxAge(-5)));
+    }
+
+    #[test]
+    fn cache_control_ignores_garbage_max_age() {
+        let headers = headers_with(warp::http::header::CACHE_CONTROL, "max-age=not-a-number");
+        assert_eq!(cache_control_ttl(&headers), None);
+    }
+
+    #[test]
+    fn cache_control_absent_header_is_none() {
+        assert_eq!(cache_control_ttl(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn expires_in_the_past_clamps_to_zero() {
+        let headers = headers_with(warp::http::header::EXPIRES, "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(expires_ttl(&headers), Some(0));
+    }
+
+    #[test]
+    fn expires_malformed_header_is_none() {
+        let headers = headers_with(warp::http::header::EXPIRES, "not a date");
+        assert_eq!(expires_ttl(&headers), None);
+    }
+
+    async fn full_path(path: &str) -> FullPath {
+        warp::test::request().path(path).filter(&warp::path::full()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn default_issuer_distinguishes_by_query_string() {
+        let uri = full_path("/report").await;
+        let issuer = DefaultCacheIssuer;
+        let key_a = issuer.issue(&Method::GET, &uri, &Some("id=1".to_owned()), &HeaderMap::new(), &Bytes::new());
+        let key_b = issuer.issue(&Method::GET, &uri, &Some("id=2".to_owned()), &HeaderMap::new(), &Bytes::new());
+        assert_ne!(key_a, key_b);
+    }
+
+    #[tokio::test]
+    async fn header_folding_issuer_distinguishes_by_configured_header() {
+        let uri = full_path("/report").await;
+        let issuer = HeaderFoldingIssuer::new(DefaultCacheIssuer, vec!["X-Tenant".to_owned()]);
+        let key_a = issuer.issue(&Method::GET, &uri, &None, &headers_with(warp::http::header::HeaderName::from_static("x-tenant"), "a"), &Bytes::new());
+        let key_b = issuer.issue(&Method::GET, &uri, &None, &headers_with(warp::http::header::HeaderName::from_static("x-tenant"), "b"), &Bytes::new());
+        assert_ne!(key_a, key_b);
+    }
+
+    #[tokio::test]
+    async fn header_folding_issuer_ignores_headers_not_listed() {
+        let uri = full_path("/report").await;
+        let issuer = HeaderFoldingIssuer::new(DefaultCacheIssuer, vec!["X-Tenant".to_owned()]);
+        let key_a = issuer.issue(&Method::GET, &uri, &None, &HeaderMap::new(), &Bytes::new());
+        let key_b = issuer.issue(&Method::GET, &uri, &None, &headers_with(warp::http::header::HeaderName::from_static("x-unrelated"), "whatever"), &Bytes::new());
+        assert_eq!(key_a, key_b);
+    }
+
+    #[tokio::test]
+    async fn header_folding_issuer_passes_through_key_when_no_headers_configured() {
+        let uri = full_path("/report").await;
+        let inner = DefaultCacheIssuer;
+        let inner_key = inner.issue(&Method::GET, &uri, &None, &HeaderMap::new(), &Bytes::new());
+        let issuer = HeaderFoldingIssuer::new(DefaultCacheIssuer, vec![]);
+        let key = issuer.issue(&Method::GET, &uri, &None, &HeaderMap::new(), &Bytes::new());
+        assert_eq!(key, inner_key);
+    }
+
+    #[tokio::test]
+    async fn path_excluding_issuer_opts_excluded_prefix_out_of_caching() {
+        let uri = full_path("/health").await;
+        let issuer = PathExcludingIssuer::new(DefaultCacheIssuer, vec!["/health".to_owned()]);
+        let key = issuer.issue(&Method::GET, &uri, &None, &HeaderMap::new(), &Bytes::new());
+        assert_eq!(key, None);
+    }
+
+    #[tokio::test]
+    async fn path_excluding_issuer_passes_through_other_paths() {
+        let uri = full_path("/report").await;
+        let inner = DefaultCacheIssuer;
+        let inner_key = inner.issue(&Method::GET, &uri, &None, &HeaderMap::new(), &Bytes::new());
+        let issuer = PathExcludingIssuer::new(DefaultCacheIssuer, vec!["/health".to_owned()]);
+        let key = issuer.issue(&Method::GET, &uri, &None, &HeaderMap::new(), &Bytes::new());
+        assert_eq!(key, inner_key);
+    }
 }
\ No newline at end of file
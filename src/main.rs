@@ -1,6 +1,6 @@
 use structopt::StructOpt;
 
-use warp::Filter;
+use warp::{Filter, Reply};
 use warp_reverse_proxy::extract_request_data_filter;
 use log::{info, error};
 
@@ -9,6 +9,10 @@ use datacache::{DataCache, CacheConfig};
 
 mod proxy;
 use proxy::{CacheProxy, ProxyConfig};
+
+mod modules;
+mod auth;
+mod admin;
 use std::sync::Arc;
 
 
@@ -54,6 +58,23 @@ impl CacheConfig for Config {
             .get_int("cache.ttl")
             .unwrap_or_else(|_| 3600)
     }
+    fn get_max_entries(&self) -> Option<usize> {
+        self.settings
+            .get_int("cache.max_entries")
+            .ok()
+            .map(|n| n as usize)
+    }
+    fn get_max_bytes(&self) -> Option<u64> {
+        self.settings
+            .get_int("cache.max_bytes")
+            .ok()
+            .map(|n| n as u64)
+    }
+    fn get_stale_while_revalidate(&self) -> i64 {
+        self.settings
+            .get_int("cache.stale_while_revalidate")
+            .unwrap_or(0)
+    }
 }
 
 impl ProxyConfig for Config {
@@ -85,6 +106,74 @@ impl ProxyConfig for Config {
             .map(|x| { config::Value::into_str(x).ok() })
             .collect()
     }
+    fn get_cache_key_headers(&self) -> Vec<String> {
+        self.settings
+            .get_array("cache.key_headers")
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|x| config::Value::into_str(x).ok())
+            .collect()
+    }
+    fn get_cache_exclude_paths(&self) -> Vec<String> {
+        self.settings
+            .get_array("cache.exclude_paths")
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|x| config::Value::into_str(x).ok())
+            .collect()
+    }
+    fn get_upstream_timeout(&self) -> std::time::Duration {
+        self.settings
+            .get_int("proxy.upstream_timeout")
+            .map(|secs| std::time::Duration::from_secs(secs as u64))
+            .unwrap_or(std::time::Duration::from_secs(120))
+    }
+    fn get_upstream_retries(&self) -> u32 {
+        self.settings
+            .get_int("proxy.upstream_retries")
+            .map(|n| n as u32)
+            .unwrap_or(0)
+    }
+    fn get_circuit_breaker_threshold(&self) -> u32 {
+        self.settings
+            .get_int("proxy.circuit_breaker_threshold")
+            .map(|n| n as u32)
+            .unwrap_or(5)
+    }
+    fn get_circuit_breaker_cooldown(&self) -> std::time::Duration {
+        self.settings
+            .get_int("proxy.circuit_breaker_cooldown")
+            .map(|secs| std::time::Duration::from_secs(secs as u64))
+            .unwrap_or(std::time::Duration::from_secs(30))
+    }
+    fn get_token_header(&self) -> Option<String> {
+        self.settings
+            .get_str("token.header")
+            .ok()
+    }
+    fn get_token_query_param(&self) -> Option<String> {
+        self.settings
+            .get_str("token.query_param")
+            .ok()
+    }
+    fn get_token_shared_secret(&self) -> Option<String> {
+        self.settings
+            .get_str("token.shared_secret")
+            .ok()
+    }
+    fn get_token_allowlist(&self) -> Vec<String> {
+        self.settings
+            .get_array("token.allowlist")
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|x| config::Value::into_str(x).ok())
+            .collect()
+    }
+    fn get_admin_token(&self) -> Option<String> {
+        self.settings
+            .get_str("admin.token")
+            .ok()
+    }
 }
 
 
@@ -101,10 +190,23 @@ async fn main() {
     let proxy = Arc::new(CacheProxy::new(cache, &config));
     let request_filter = extract_request_data_filter();
 
-    let app = warp::any()
-        .map(move || { Arc::clone(&proxy) })
+    // only intercept the `_cache/*` prefix when an admin token is actually
+    // configured, so a real upstream route under that prefix isn't shadowed
+    // by a permanent 404 from the (otherwise disabled) admin API
+    let admin_token_configured = proxy.admin_token().is_some();
+
+    let proxy_for_route = Arc::clone(&proxy);
+    let proxy_route = warp::any()
+        .map(move || Arc::clone(&proxy_for_route))
         .and(request_filter)
-        .and_then(CacheProxy::handle_request);
+        .and_then(CacheProxy::handle_request)
+        .map(|res: warp::http::Response<warp::hyper::body::Bytes>| res.into_response());
+
+    let app = if admin_token_configured {
+        admin::routes(Arc::clone(&proxy)).or(proxy_route).unify().boxed()
+    } else {
+        proxy_route.boxed()
+    };
 
     warp::serve(app).run(([0, 0, 0, 0], config.get_port())).await;
 }
@@ -0,0 +1,96 @@
+use async_trait::async_trait;
+use log::info;
+use tokio::io::AsyncWriteExt; // for write_all()
+
+use warp::{http::Response, hyper::body::Bytes};
+
+use crate::proxy::{Decision, ProxyModule, RequestContext};
+
+
+/// Ported from the original inline `filter_body`: only lets a response be
+/// written to the cache if its first 12 bytes match one of the configured
+/// magic patterns (or no patterns are configured, i.e. filtering is off).
+pub struct IncludeFilterModule {
+    patterns: Vec<Option<String>>,
+}
+
+impl IncludeFilterModule {
+    pub fn new(patterns: Vec<Option<String>>) -> Self {
+        IncludeFilterModule { patterns }
+    }
+}
+
+#[async_trait]
+impl ProxyModule for IncludeFilterModule {
+    async fn on_cache_store(&self, ctx: &RequestContext, response: &Response<Bytes>) -> bool {
+        // active only if filters count > 0
+        if self.patterns.is_empty() {
+            return true;
+        }
+        const MAGIC_LEN: usize = 12;
+        // check for body len, prevent panics
+        let body = response.body();
+        if body.len() < MAGIC_LEN {
+            return false;
+        }
+        // read first MAGIC_LEN bytes
+        let magic = &body[..MAGIC_LEN];
+        let matched = self.patterns.iter().any(|pattern| match pattern {
+            Some(pattern) => pattern.as_bytes() == magic,
+            None => true,
+        });
+        if !matched {
+            if let Some(hash) = &ctx.hash {
+                info!(
+                    "[{}] response blocked by filter, not saved to cache",
+                    &hash[..6]
+                );
+            }
+        }
+        matched
+    }
+}
+
+/// Ported from the original inline `save_body`: dumps the raw request
+/// body to `path/<hash>` for debugging, if the request was issued a
+/// cache key.
+pub struct DebugBodySaverModule {
+    path: String,
+}
+
+impl DebugBodySaverModule {
+    pub fn new(path: String) -> Self {
+        DebugBodySaverModule { path }
+    }
+
+    async fn save(&self, hash: &str, body: &Bytes) -> std::io::Result<()> {
+        // skip write empty files ;)
+        if body.is_empty() {
+            info!(
+                "[{}] body empty, skip saving...",
+                &hash[..6]
+            );
+            return Ok(());
+        }
+        // ensure all path to file created
+        std::fs::create_dir_all(&self.path)?;
+
+        let mut file = tokio::fs::File::create(format!("{}/{}", self.path, hash)).await?;
+        file.write_all(body).await?;
+        info!(
+            "[{}] body saved to file!",
+            &hash[..6]
+        );
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ProxyModule for DebugBodySaverModule {
+    async fn on_request(&self, ctx: &mut RequestContext) -> Decision {
+        if let Some(hash) = ctx.hash.clone() {
+            let _ = self.save(&hash, &ctx.body).await;
+        }
+        Decision::Continue
+    }
+}
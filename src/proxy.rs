@@ -1,7 +1,7 @@
 use warp::{
     http::Response,
     http::StatusCode,
-    Rejection, 
+    Rejection,
     hyper::body::Bytes,
     hyper::HeaderMap,
     filters::path::FullPath
@@ -13,11 +13,18 @@ use warp_reverse_proxy::{
     Method,
 };
 
-use log::{info, error};
-use std::time::Instant;
-use tokio::io::AsyncWriteExt; // for write_all()
+use async_trait::async_trait;
+use log::{info, error, warn};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use crate::datacache::{DataCache, rq_hash_string};
+use crate::auth::{fold_identity, TokenOutcome, TokenValidator};
+use crate::datacache::{
+    CacheStats, DataCache, CacheIssuer, CacheLookup, DefaultCacheIssuer, EntryMeta, HeaderFoldingIssuer, PathExcludingIssuer
+};
+use crate::modules::{DebugBodySaverModule, IncludeFilterModule};
 
 
 pub trait ProxyConfig {
@@ -26,154 +33,512 @@ pub trait ProxyConfig {
     fn get_base_path(&self) -> String { String::default() }
     fn get_rq_save_path(&self) -> Option<String> { None }
     fn get_filter_include(&self) -> Vec<Option<String>> { vec!() }
+    /// Request headers whose values are folded into the cache key.
+    fn get_cache_key_headers(&self) -> Vec<String> { vec!() }
+    /// Path prefixes that are never cached, regardless of method or status.
+    fn get_cache_exclude_paths(&self) -> Vec<String> { vec!() }
+    /// Deadline for a single upstream call.
+    fn get_upstream_timeout(&self) -> Duration { Duration::from_secs(120) }
+    /// Number of retries after the first failed/timed-out attempt.
+    fn get_upstream_retries(&self) -> u32 { 0 }
+    /// Consecutive upstream failures before the circuit breaker opens.
+    fn get_circuit_breaker_threshold(&self) -> u32 { 5 }
+    /// How long the circuit breaker stays open before the next attempt.
+    fn get_circuit_breaker_cooldown(&self) -> Duration { Duration::from_secs(30) }
+    /// Header carrying the client token. Token gating is disabled unless
+    /// this or `get_token_query_param` is set.
+    fn get_token_header(&self) -> Option<String> { None }
+    /// Query parameter carrying the client token, as an alternative to a header.
+    fn get_token_query_param(&self) -> Option<String> { None }
+    /// Shared secret used to verify signed `<identity>:<expiry>.<mac>`
+    /// tokens. Unset falls back to `get_token_allowlist`.
+    fn get_token_shared_secret(&self) -> Option<String> { None }
+    /// Static allowlist of accepted opaque tokens, used when no shared
+    /// secret is configured.
+    fn get_token_allowlist(&self) -> Vec<String> { vec!() }
+    /// Shared token required to reach the admin API (`/_cache/...`
+    /// routes). Unset disables the admin API entirely.
+    fn get_admin_token(&self) -> Option<String> { None }
+}
+
+/// Why a call to the upstream backend did not produce a response.
+#[derive(Debug)]
+enum UpstreamFailure {
+    Timeout,
+    Connection(Rejection),
+}
+
+/// Per-request state threaded through the module pipeline.
+pub struct RequestContext {
+    pub method: Method,
+    pub uri: FullPath,
+    pub params: QueryParameters,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+    /// Cache key for this request, or `None` if the configured
+    /// `CacheIssuer` opted it out of caching.
+    pub hash: Option<String>,
+}
+
+/// What a module wants done with the current request.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Decision {
+    Continue,
+    /// Proxy the request as usual, but don't look up or store it in the cache.
+    SkipCache,
+}
+
+/// A pluggable unit of request/response processing. `CacheProxy` runs an
+/// ordered list of these instead of baking hooks like body filtering or
+/// debug dumps directly into `handle_request`.
+#[async_trait]
+pub trait ProxyModule: Send + Sync {
+    /// Called once per incoming request, before the cache is consulted.
+    async fn on_request(&self, _ctx: &mut RequestContext) -> Decision { Decision::Continue }
+
+    /// Called after a successful upstream response, before it's returned
+    /// to the client or considered for caching. May rewrite the response
+    /// in place (headers, body).
+    async fn on_upstream_response(&self, _ctx: &RequestContext, _response: &mut Response<Bytes>) {}
+
+    /// Called just before a `200 OK` response would be written to the
+    /// cache. Returning `false` skips storing it.
+    async fn on_cache_store(&self, _ctx: &RequestContext, _response: &Response<Bytes>) -> bool { true }
 }
 
 pub struct CacheProxy {
     cache: DataCache,
+    issuer: Box<dyn CacheIssuer>,
+    token_validator: TokenValidator,
+    admin_token: Option<String>,
+    modules: Vec<Arc<dyn ProxyModule>>,
     proxy_address: String,
     host: String,
     base_path: String,
-    rq_save_path: Option<String>,
-    filter_include: Vec<Option<String>>
+    upstream_timeout: Duration,
+    upstream_retries: u32,
+    circuit_breaker_threshold: u32,
+    circuit_breaker_cooldown: Duration,
+    consecutive_failures: AtomicU32,
+    circuit_open_until: Mutex<Option<Instant>>,
+    revalidating: Mutex<HashSet<String>>,
 }
 
 impl CacheProxy {
     pub fn new<T: ProxyConfig>(cache: DataCache, config: &T) -> Self {
+        let issuer = PathExcludingIssuer::new(
+            HeaderFoldingIssuer::new(DefaultCacheIssuer, config.get_cache_key_headers()),
+            config.get_cache_exclude_paths()
+        );
+
+        let mut modules: Vec<Arc<dyn ProxyModule>> = Vec::new();
+        modules.push(Arc::new(IncludeFilterModule::new(config.get_filter_include())));
+        if let Some(path) = config.get_rq_save_path() {
+            modules.push(Arc::new(DebugBodySaverModule::new(path)));
+        }
+
+        let token_validator = TokenValidator::new(
+            config.get_token_header(),
+            config.get_token_query_param(),
+            config.get_token_shared_secret(),
+            config.get_token_allowlist(),
+        );
+
         CacheProxy {
             cache,
+            issuer: Box::new(issuer),
+            token_validator,
+            admin_token: config.get_admin_token(),
+            modules,
             proxy_address: config.get_proxy_address(),
             host: config.get_host(),
             base_path: config.get_base_path(),
-            rq_save_path: config.get_rq_save_path(),
-            filter_include: config.get_filter_include()
+            upstream_timeout: config.get_upstream_timeout(),
+            upstream_retries: config.get_upstream_retries(),
+            circuit_breaker_threshold: config.get_circuit_breaker_threshold(),
+            circuit_breaker_cooldown: config.get_circuit_breaker_cooldown(),
+            consecutive_failures: AtomicU32::new(0),
+            circuit_open_until: Mutex::new(None),
+            revalidating: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Runs every module's `on_request` hook in order. The overall
+    /// decision is `SkipCache` if any module asked for it.
+    async fn run_on_request(&self, ctx: &mut RequestContext) -> Decision {
+        let mut decision = Decision::Continue;
+        for module in &self.modules {
+            if module.on_request(ctx).await == Decision::SkipCache {
+                decision = Decision::SkipCache;
+            }
+        }
+        decision
+    }
+
+    /// Runs every module's `on_upstream_response` hook in order, letting
+    /// each rewrite the response in place.
+    async fn run_on_upstream_response(&self, ctx: &RequestContext, response: &mut Response<Bytes>) {
+        for module in &self.modules {
+            module.on_upstream_response(ctx, response).await;
         }
     }
 
-    /// Apply body filter for first 12 bytes, if defined
-    fn filter_body(&self, body: &Bytes) -> bool {
-        // active only if filters count > 0
-        if self.filter_include.len() > 0 {
-            const MAGIC_LEN: usize = 12;
-            // check for body len, prevent panics
-            if body.len() < MAGIC_LEN {
+    /// Runs every module's `on_cache_store` hook; all must agree to
+    /// allow the response to be written to the cache.
+    async fn run_on_cache_store(&self, ctx: &RequestContext, response: &Response<Bytes>) -> bool {
+        for module in &self.modules {
+            if !module.on_cache_store(ctx, response).await {
                 return false;
             }
-            // read first MAGIC_LEN bytes
-            let magic = &body[..MAGIC_LEN];
-            let count = self.filter_include
-                .iter()
-                .filter(|f| {
-                    if let Some(pattern) = f {
-                        return pattern.as_bytes() == magic;
-                    }
-                    true
-                })
-                .count();
-            return count > 0;
-        }   
+        }
         true
     }
 
-    /// Save body to file if rq_save_path is set (debug mode)
-    async fn save_body(&self, hash: &str, body: &Bytes) -> std::io::Result<()> {
-        if let Some(path) = self.rq_save_path.as_deref() {
-            // skip write empty files ;)
-            if body.len() == 0 {
-                info!(
-                    "[{}] body empty, skip saving...",
-                    &hash[..6]
-                );
-                return Ok(())
+    /// `true` while the circuit breaker is open, i.e. while we're inside
+    /// the cooldown window following `circuit_breaker_threshold`
+    /// consecutive upstream failures.
+    fn circuit_is_open(&self) -> bool {
+        match *self.circuit_open_until.lock().unwrap() {
+            Some(until) => Instant::now() < until,
+            None => false,
+        }
+    }
+
+    fn record_upstream_success(&self) {
+        self.consecutive_failures.store(0, Ordering::Relaxed);
+        *self.circuit_open_until.lock().unwrap() = None;
+    }
+
+    fn record_upstream_failure(&self) {
+        let failures = self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures >= self.circuit_breaker_threshold {
+            warn!(
+                "circuit breaker open after {} consecutive upstream failures, cooldown={}s",
+                failures, self.circuit_breaker_cooldown.as_secs()
+            );
+            *self.circuit_open_until.lock().unwrap() = Some(Instant::now() + self.circuit_breaker_cooldown);
+        }
+    }
+
+    /// Calls the upstream backend under a deadline, retrying up to
+    /// `upstream_retries` times with a short exponential backoff.
+    async fn call_upstream(
+        &self,
+        uri: FullPath,
+        params: QueryParameters,
+        method: Method,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> Result<Response<Bytes>, UpstreamFailure> {
+        let mut attempt = 0;
+        loop {
+            let call = proxy_to_and_forward_response(
+                self.proxy_address.to_owned(),
+                self.base_path.to_owned(),
+                uri.clone(),
+                params.clone(),
+                method.clone(),
+                headers.clone(),
+                body.clone()
+            );
+
+            let outcome = match tokio::time::timeout(self.upstream_timeout, call).await {
+                Ok(Ok(res)) => return Ok(res),
+                Ok(Err(err)) => UpstreamFailure::Connection(err),
+                Err(_elapsed) => UpstreamFailure::Timeout,
+            };
+
+            if attempt >= self.upstream_retries {
+                return Err(outcome);
+            }
+            attempt += 1;
+            let backoff = Duration::from_millis(100 * 2u64.pow(attempt.min(4)));
+            warn!("upstream attempt {} failed ({:?}), retrying in {:?}", attempt, outcome, backoff);
+            tokio::time::sleep(backoff).await;
+        }
+    }
+
+    /// Serves a stale cached entry (if any) as a fallback when the
+    /// circuit breaker is open or the upstream call ultimately failed.
+    fn fallback_response(&self, hash: Option<&str>) -> Response<Bytes> {
+        if let Some(hash) = hash {
+            if let Ok(Some(stale)) = self.cache.get_stale(hash) {
+                return stale;
             }
-            // ensure all path to file created
-            std::fs::create_dir_all(path)?;
+        }
+        Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .body(Bytes::from_static(b"upstream unavailable"))
+            .expect("static response is always valid")
+    }
+
+    /// Short plain-text response for a rejected token.
+    fn auth_error(status: StatusCode, message: &'static str) -> Response<Bytes> {
+        Response::builder()
+            .status(status)
+            .body(Bytes::from_static(message.as_bytes()))
+            .expect("static response is always valid")
+    }
 
-            let mut file = tokio::fs::File::create(format!("{}/{}", path, hash)).await?;
-            file.write_all(body).await?;
+    /// Stores `res` under `hash` if it's a cacheable `200 OK` that every
+    /// module's `on_cache_store` hook approves. Shared by the normal
+    /// request path and background revalidation.
+    async fn store_if_cacheable(&self, hash: &str, ctx: &RequestContext, res: &Response<Bytes>) {
+        if res.status() != StatusCode::OK {
             info!(
-                "[{}] body saved to file!",
-                &hash[..6]
+                "[{}] response status code={}, not saved to cache",
+                &hash[..6], res.status()
             );
+            return;
+        }
+        if !self.run_on_cache_store(ctx, res).await {
+            return;
+        }
+
+        let timer = Instant::now();
+        if let Err(e) = self.cache.insert(hash, res) {
+            error!(
+                "[{}] error saving response to cache, {}",
+                &hash[..6], e
+            )
+        } else {
+            info!(
+                "[{}] new response saved to cache, elapsed={} ms",
+                &hash[..6], timer.elapsed().as_millis()
+            )
         }
-        Ok(())
+    }
+
+    /// Re-fetches `hash` from the origin in the background and refreshes
+    /// the cache entry, so stale-while-revalidate callers never block on
+    /// revalidation. Does nothing if the circuit breaker is open (the
+    /// origin is already known to be down) or if `hash` is already being
+    /// revalidated by another in-flight request.
+    fn spawn_revalidation(self: &Arc<Self>, hash: String, ctx: RequestContext) {
+        if self.circuit_is_open() {
+            info!("[{}] circuit breaker open, skipping background revalidation", &hash[..6]);
+            return;
+        }
+        if !self.revalidating.lock().unwrap().insert(hash.clone()) {
+            info!("[{}] revalidation already in flight, skipping", &hash[..6]);
+            return;
+        }
+
+        let this = Arc::clone(self);
+        tokio::spawn(async move {
+            info!("[{}] revalidating stale entry in background", &hash[..6]);
+            let mut headers = ctx.headers.clone();
+            headers.insert("Host", this.host.parse().unwrap());
+            match this.call_upstream(ctx.uri.clone(), ctx.params.clone(), ctx.method.clone(), headers, ctx.body.clone()).await {
+                Ok(mut res) => {
+                    this.record_upstream_success();
+                    this.run_on_upstream_response(&ctx, &mut res).await;
+                    this.store_if_cacheable(&hash, &ctx, &res).await;
+                }
+                Err(failure) => {
+                    error!("[{}] background revalidation failed: {:?}", &hash[..6], failure);
+                    this.record_upstream_failure();
+                }
+            }
+            this.revalidating.lock().unwrap().remove(&hash);
+        });
+    }
+
+    /// Token expected from callers of the admin API, if the admin API is enabled.
+    pub fn admin_token(&self) -> Option<&str> {
+        self.admin_token.as_deref()
+    }
+
+    /// Current entry count and total size, for `GET /_cache/stats`.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    /// Metadata for a single cached entry, for `GET /_cache/entry/{hash}`.
+    pub fn cache_peek(&self, hash: &str) -> Result<Option<EntryMeta>, Box<dyn std::error::Error>> {
+        self.cache.peek(hash)
+    }
+
+    /// Purges a single cached entry, for `DELETE /_cache/entry/{hash}`.
+    pub fn cache_purge(&self, hash: &str) -> bool {
+        self.cache.purge(hash)
+    }
+
+    /// Purges every entry whose key starts with `prefix`, for
+    /// `DELETE /_cache/entries?prefix=...`.
+    pub fn cache_purge_prefix(&self, prefix: &str) -> usize {
+        self.cache.purge_prefix(prefix)
+    }
+
+    /// Purges the entire cache, for `DELETE /_cache/entries`.
+    pub fn cache_clear(&self) -> usize {
+        self.cache.clear()
     }
 
     pub async fn handle_request(
-        self: std::sync::Arc<CacheProxy>,
+        self: Arc<CacheProxy>,
         uri: FullPath,
         params: QueryParameters,
         method: Method,
-        mut headers: HeaderMap,
+        headers: HeaderMap,
         body: Bytes,
     ) -> Result<Response<Bytes>, Rejection> {
-        // calculate hash for request 
-        let hash = rq_hash_string(&uri, &body);
+        // gate on the client token before consulting the cache or
+        // proxying anywhere near upstream
+        let identity = match self.token_validator.validate(&headers, &params) {
+            TokenOutcome::Valid(identity) => identity,
+            TokenOutcome::Missing => {
+                warn!("request rejected, missing client token");
+                return Ok(Self::auth_error(StatusCode::UNAUTHORIZED, "missing token"));
+            }
+            TokenOutcome::Expired => {
+                warn!("request rejected, expired client token");
+                return Ok(Self::auth_error(StatusCode::UNAUTHORIZED, "expired token"));
+            }
+            TokenOutcome::Invalid => {
+                warn!("request rejected, invalid client token");
+                return Ok(Self::auth_error(StatusCode::FORBIDDEN, "invalid token"));
+            }
+        };
+
+        // ask the configured issuer for a cache key; None means this
+        // request is never looked up or stored in the cache. Fold the
+        // validated token identity in so different clients never share
+        // cached entries.
+        let issued_hash = self.issuer.issue(&method, &uri, &params, &headers, &body)
+            .map(|hash| if identity.is_empty() { hash } else { fold_identity(&hash, &identity) });
+        let log_id = issued_hash.as_deref().unwrap_or("------").to_owned();
         info!(
             "[{}] received new request, {}, body len={}",
-            &hash[..6], uri.as_str(), body.len()
+            &log_id[..6], uri.as_str(), body.len()
         );
 
-        // save request body to file if config present, ignore errors...
-        let _ = self.save_body(&hash, &body).await;
+        let mut ctx = RequestContext {
+            method: method.clone(), uri: uri.clone(), params: params.clone(),
+            headers: headers.clone(), body: body.clone(), hash: issued_hash,
+        };
+        let decision = self.run_on_request(&mut ctx).await;
+        let hash = if decision == Decision::SkipCache { None } else { ctx.hash.clone() };
+
+        if let Some(hash) = hash.as_deref() {
+            // find saved response body in cache database
+            if method == Method::GET || method == Method::POST {
+                match self.cache.get(hash) {
+                    Ok(CacheLookup::Fresh(response)) => {
+                        info!("[{}] return cached response", &hash[..6]);
+                        return Ok(response)
+                    }
+                    Ok(CacheLookup::Stale(response)) => {
+                        info!("[{}] return stale cached response, revalidating", &hash[..6]);
+                        self.spawn_revalidation(hash.to_owned(), ctx);
+                        return Ok(response)
+                    }
+                    Ok(CacheLookup::Miss) | Err(_) => {}
+                }
+            }
+        } else {
+            info!(
+                "[{}] request not cacheable, bypassing cache",
+                log_id
+            );
+        }
 
-        // find saved response body in cache database
-        if method == Method::GET || method == Method::POST {
-            if let Ok(Some(response)) = self.cache.get(&hash) {
-                info!(
-                    "[{}] return cached response",
-                    &hash[..6]
-                );
-                return Ok(response)
-            } 
+        // if a dead backend already tripped the circuit breaker, don't
+        // bother hammering it again until the cooldown elapses
+        if self.circuit_is_open() {
+            info!("[{}] circuit breaker open, serving fallback", log_id);
+            return Ok(self.fallback_response(hash.as_deref()));
         }
 
         // continue processing with request to destination service
         // insert host header from config
+        let mut headers = headers;
         headers.insert("Host", self.host.parse().unwrap());
         // proxy to destination and return response
-        match proxy_to_and_forward_response(
-            self.proxy_address.to_owned(),
-            self.base_path.to_owned(),
-            uri,
-            params,
-            method,
-            headers,
-            body
-        ).await {
-            Ok(res) => {
-                // save body to cache only if OK 200
-                if res.status() == StatusCode::OK {
-                    // test body against filter
-                    if !self.filter_body(&res.body()) {
-                        info!(
-                            "[{}] response blocked by filter, not saved to cache",
-                            &hash[..6]
-                        );
-                        return Ok(res);
-                    }
-
-                    let timer = Instant::now();
-                    if let Err(e) = self.cache.insert(&hash, &res) {
-                        error!(
-                            "[{}] error saving response to cache, {}",
-                            &hash[..6], e
-                        )
-                    } else {
-                        info!(
-                            "[{}] new response saved to cache, elapsed={} ms",
-                            &hash[..6], timer.elapsed().as_millis()
-                        )
-                    }
-                } else {
-                    info!(
-                        "[{}] response status code={}, not saved to cache",
-                        &hash[..6], res.status()
-                    )
+        match self.call_upstream(uri, params, method, headers, body).await {
+            Ok(mut res) => {
+                self.record_upstream_success();
+                self.run_on_upstream_response(&ctx, &mut res).await;
+                // save body to cache only if the request is cacheable
+                if let Some(hash) = hash.as_deref() {
+                    self.store_if_cacheable(hash, &ctx, &res).await;
                 }
                 // return response
                 Ok(res)
             }
-            Err(err) => Err(err)
+            Err(failure) => {
+                error!("[{}] upstream call failed: {:?}", log_id, failure);
+                self.record_upstream_failure();
+                Ok(self.fallback_response(hash.as_deref()))
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datacache::CacheConfig;
+
+    struct TestCacheConfig(String);
+
+    impl CacheConfig for TestCacheConfig {
+        fn get_db_path(&self) -> String { self.0.clone() }
+    }
+
+    struct TestProxyConfig {
+        circuit_breaker_threshold: u32,
+        circuit_breaker_cooldown: Duration,
+    }
+
+    impl ProxyConfig for TestProxyConfig {
+        fn get_proxy_address(&self) -> String { String::new() }
+        fn get_circuit_breaker_threshold(&self) -> u32 { self.circuit_breaker_threshold }
+        fn get_circuit_breaker_cooldown(&self) -> Duration { self.circuit_breaker_cooldown }
+    }
+
+    fn test_proxy(name: &str, threshold: u32, cooldown: Duration) -> CacheProxy {
+        let path = std::env::temp_dir().join(format!("fsight-datacache-proxy-test-{}", name));
+        let _ = std::fs::remove_dir_all(&path);
+        let cache = DataCache::new(&TestCacheConfig(path.to_string_lossy().into_owned()));
+        CacheProxy::new(cache, &TestProxyConfig {
+            circuit_breaker_threshold: threshold,
+            circuit_breaker_cooldown: cooldown,
+        })
+    }
+
+    // `DataCache::new` spawns its sweeper task, so these need a Tokio runtime
+    #[tokio::test]
+    async fn circuit_opens_once_threshold_is_reached() {
+        let proxy = test_proxy("opens-at-threshold", 3, Duration::from_secs(30));
+
+        proxy.record_upstream_failure();
+        proxy.record_upstream_failure();
+        assert!(!proxy.circuit_is_open());
+
+        proxy.record_upstream_failure();
+        assert!(proxy.circuit_is_open());
+    }
+
+    #[tokio::test]
+    async fn circuit_closes_again_after_cooldown_elapses() {
+        let proxy = test_proxy("closes-after-cooldown", 1, Duration::from_millis(20));
+
+        proxy.record_upstream_failure();
+        assert!(proxy.circuit_is_open());
+
+        std::thread::sleep(Duration::from_millis(40));
+        assert!(!proxy.circuit_is_open());
+    }
+
+    #[tokio::test]
+    async fn success_resets_the_failure_count() {
+        let proxy = test_proxy("success-resets-count", 2, Duration::from_secs(30));
+
+        proxy.record_upstream_failure();
+        proxy.record_upstream_success();
+        proxy.record_upstream_failure();
+
+        assert!(!proxy.circuit_is_open());
+    }
+}